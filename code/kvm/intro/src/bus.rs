@@ -0,0 +1,72 @@
+use std::collections::BTreeMap;
+
+/// A memory-mapped or port-mapped peripheral, addressed relative to wherever
+/// the owning `Bus` registered it. `Send` so a `Bus` can be shared across
+/// vCPU threads behind a `Mutex`
+pub trait Device: Send {
+    fn read(&mut self, offset: u64, data: &mut [u8]);
+    fn write(&mut self, offset: u64, data: &[u8]);
+}
+
+struct Entry {
+    len: u64,
+    device: Box<dyn Device>,
+}
+
+/// Routes reads/writes at an absolute address to whichever registered `Device`
+/// owns it, used for both `KVM_EXIT_MMIO` (guest-physical addresses) and
+/// `KVM_EXIT_IO` (port numbers)
+#[derive(Default)]
+pub struct Bus {
+    devices: BTreeMap<u64, Entry>,
+}
+
+impl Bus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers `device` to own the `[base, base + len)` range. Panics if it
+    /// overlaps an already-registered device
+    pub fn register(&mut self, base: u64, len: u64, device: Box<dyn Device>) {
+        // A device starting at or before `base` may reach into our range; any
+        // device starting inside `[base, base + len)` overlaps it outright
+        assert!(
+            self.find(base).is_none() && self.devices.range(base..base + len).next().is_none(),
+            "device at {base:#x}..{:#x} overlaps an already-registered device",
+            base + len
+        );
+
+        self.devices.insert(base, Entry { len, device });
+    }
+
+    fn find(&mut self, addr: u64) -> Option<(u64, &mut Entry)> {
+        self.devices
+            .range_mut(..=addr)
+            .next_back()
+            .filter(|(&base, entry)| addr < base + entry.len)
+            .map(|(&base, entry)| (base, entry))
+    }
+
+    /// Returns `true` if a registered device owned `addr` and handled the read
+    pub fn read(&mut self, addr: u64, data: &mut [u8]) -> bool {
+        match self.find(addr) {
+            Some((base, entry)) => {
+                entry.device.read(addr - base, data);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns `true` if a registered device owned `addr` and handled the write
+    pub fn write(&mut self, addr: u64, data: &[u8]) -> bool {
+        match self.find(addr) {
+            Some((base, entry)) => {
+                entry.device.write(addr - base, data);
+                true
+            }
+            None => false,
+        }
+    }
+}
@@ -1,211 +1,274 @@
-use intro::WrappedAutoFree;
+mod bus;
+mod memory;
+mod serial;
+mod snapshot;
+mod vcpu;
+mod vm;
+
+use bus::{Bus, Device};
 use kvm_bindings::{
-    kvm_regs, kvm_run, kvm_sregs, kvm_userspace_memory_region, KVMIO, KVM_EXIT_HLT, KVM_EXIT_IO,
+    kvm_regs, KVM_EXIT_HLT, KVM_EXIT_IO, KVM_EXIT_IO_IN, KVM_EXIT_IO_OUT, KVM_EXIT_MMIO,
 };
 use nix::{
-    fcntl,
-    fcntl::OFlag,
-    ioctl_read, ioctl_write_int_bad, ioctl_write_ptr, request_code_none,
-    sys::{mman, mman::MapFlags, mman::ProtFlags, stat::Mode},
+    poll::{PollFd, PollFlags},
+    unistd,
 };
-use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
-use std::{env, fs::File, io::Read, num::NonZeroUsize, os::fd::BorrowedFd};
-
-ioctl_write_int_bad!(kvm_create_vm, request_code_none!(KVMIO, 0x01));
-ioctl_write_int_bad!(kvm_get_vcpu_mmap_size, request_code_none!(KVMIO, 0x04));
-ioctl_write_int_bad!(kvm_run, request_code_none!(KVMIO, 0x80));
-ioctl_write_int_bad!(kvm_create_vcpu, request_code_none!(KVMIO, 0x41));
-ioctl_write_ptr!(
-    kvm_set_user_memory_region,
-    KVMIO,
-    0x46,
-    kvm_userspace_memory_region
-);
-ioctl_read!(kvm_get_regs, KVMIO, 0x81, kvm_regs);
-ioctl_write_ptr!(kvm_set_regs, KVMIO, 0x82, kvm_regs);
-ioctl_read!(kvm_get_sregs, KVMIO, 0x83, kvm_sregs);
-ioctl_write_ptr!(kvm_set_sregs, KVMIO, 0x84, kvm_sregs);
-
-struct Kvm {
-    /// KVM subsystem handle
-    kvm: OwnedFd,
-    /// VM handle
-    vm: OwnedFd,
-    /// vCPU handle
-    vcpu: OwnedFd,
-    /// Shared kvm_run structure for communication
-    kvm_run: WrappedAutoFree<*mut kvm_run, Box<dyn FnOnce(*mut kvm_run)>>,
-}
-
-impl Kvm {
-    pub fn new() -> Result<Self, std::io::Error> {
-        let kvm =
-            unsafe { OwnedFd::from_raw_fd(fcntl::open("/dev/kvm", OFlag::O_RDWR, Mode::empty())?) };
-        let vm = unsafe { OwnedFd::from_raw_fd(kvm_create_vm(kvm.as_raw_fd(), 0)?) };
-        let vcpu = unsafe { OwnedFd::from_raw_fd(kvm_create_vcpu(vm.as_raw_fd(), 0)?) };
-
-        // Size of the shared `kvm_run` mapping
-        let mmap_size = NonZeroUsize::new(unsafe {
-            kvm_get_vcpu_mmap_size(kvm.as_raw_fd(), 0)?
-                .try_into()
-                .expect("mmap_size too big for usize!")
-        })
-        .expect("mmap_size is zero");
-
-        let kvm_run = WrappedAutoFree::new(
-            unsafe {
-                mman::mmap(
-                    None,
-                    mmap_size,
-                    ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
-                    MapFlags::MAP_SHARED,
-                    Some(&vcpu),
-                    0,
-                )? as *mut kvm_run
-            },
-            Box::new(move |map: *mut kvm_run| unsafe {
-                mman::munmap(map as _, mmap_size.get()).expect("failed to unmap kvm_run!");
-            }) as _,
-        );
-
-        Ok(Self {
-            kvm,
-            vm,
-            vcpu,
-            kvm_run,
-        })
-    }
-
-    pub fn set_user_memory_region(
-        &self,
-        guest_phys_addr: u64,
-        memory_size: usize,
-        userspace_addr: u64,
-    ) -> Result<(), std::io::Error> {
-        unsafe {
-            kvm_set_user_memory_region(
-                self.vm.as_raw_fd(),
-                &kvm_userspace_memory_region {
-                    slot: 0,
-                    flags: 0,
-                    guest_phys_addr,
-                    memory_size: memory_size as u64,
-                    userspace_addr,
-                },
-            )?;
-        }
-
-        Ok(())
-    }
+use serial::Serial;
+use snapshot::Snapshot;
+use std::{
+    env,
+    fs::File,
+    io::Read,
+    os::fd::AsRawFd,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+use vm::VmBuilder;
 
-    pub fn get_vcpu_sregs(&self) -> Result<kvm_sregs, std::io::Error> {
-        let mut sregs = kvm_sregs::default();
-        unsafe { kvm_get_sregs(self.vcpu.as_raw_fd(), &mut sregs)? };
+/// Flips `halted` when dropped, including when a thread's closure panics and
+/// unwinds - so one vCPU thread dying doesn't leave the others spinning on a
+/// flag nobody ever sets
+struct HaltOnDrop(Arc<AtomicBool>);
 
-        Ok(sregs)
+impl Drop for HaltOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Release);
     }
+}
 
-    pub fn set_vcpu_sregs(&self, regs: *const kvm_sregs) -> Result<(), std::io::Error> {
-        unsafe { kvm_set_sregs(self.vcpu.as_raw_fd(), regs)? };
-
-        Ok(())
+/// Lets a `Serial` be registered on a `Bus` and still be reachable from the
+/// run loop, to feed it host stdin
+impl Device for Arc<Mutex<Serial>> {
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        self.lock().unwrap().read(offset, data);
     }
 
-    pub fn get_vcpu_regs(&self) -> Result<kvm_regs, std::io::Error> {
-        let mut regs = kvm_regs::default();
-        unsafe { kvm_get_regs(self.vcpu.as_raw_fd(), &mut regs)? };
-
-        Ok(regs)
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        self.lock().unwrap().write(offset, data);
     }
+}
 
-    pub fn set_vcpu_regs(&self, regs: *const kvm_regs) -> Result<(), std::io::Error> {
-        unsafe { kvm_set_regs(self.vcpu.as_raw_fd(), regs)? };
-
-        Ok(())
+/// Drains whatever host stdin has buffered, without blocking, into `serial`
+///
+/// Pinned to the nix <= 0.27 `poll`/`mmap` API throughout this crate (a plain
+/// `libc::c_int` timeout here, `Option<BorrowedFd>` + raw-pointer casts in
+/// `memory.rs`/`vcpu.rs`) - nix 0.28 reworked both `mmap`/`munmap` and
+/// `poll`'s timeout type, and the two halves don't mix
+fn poll_stdin(serial: &Arc<Mutex<Serial>>) -> Result<(), std::io::Error> {
+    let stdin = std::io::stdin();
+    let mut fds = [PollFd::new(&stdin, PollFlags::POLLIN)];
+
+    // A zero timeout makes this a non-blocking poll
+    if nix::poll::poll(&mut fds, 0)? == 0 {
+        return Ok(());
     }
 
-    pub fn run(&self) -> Result<*const kvm_run, std::io::Error> {
-        unsafe {
-            kvm_run(self.vcpu.as_raw_fd(), 0)?;
-        }
+    let mut buf = [0u8; 64];
+    let read = unistd::read(stdin.as_raw_fd(), &mut buf)?;
+    serial.lock().unwrap().enqueue_input(&buf[..read]);
 
-        // The `kvm_run` struct is filled with new data as it was associated
-        // with the `vcpu` FD in the mmap() call
-        Ok(*self.kvm_run as _)
-    }
+    Ok(())
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     // We don't need a large mapping as our code is tiny
     // Must be page-size aligned, so minimum is 4KiB
     const MAP_SIZE: usize = 0x1000;
+    const STACK_SIZE: usize = 0x1000;
+    // Guest-physical address the per-cpu stacks start at, above the code mapping
+    const STACKS_BASE: u64 = 0x1000;
+
+    let mut args = env::args().peekable();
+    let program = args.next().expect("no argv[0]");
+    let path = args.next().unwrap_or_else(|| {
+        eprintln!("usage: {program} <code> [vcpu-count] [--snapshot <path>]");
+        std::process::exit(1);
+    });
+    // vcpu-count may be left out even when --snapshot is given
+    let vcpu_count: u64 = args
+        .next_if(|arg| !arg.starts_with("--"))
+        .map(|n| n.parse().expect("vcpu-count must be a number"))
+        .unwrap_or(1);
+
+    // When given, vCPU 0 resumes from this snapshot instead of a fresh reset
+    // state if the file already exists, and dumps its state here on HLT -
+    // letting a run be checkpointed and later resumed
+    let mut snapshot_path = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--snapshot" => {
+                snapshot_path = Some(args.next().unwrap_or_else(|| {
+                    eprintln!("--snapshot requires a path");
+                    std::process::exit(1);
+                }));
+            }
+            arg => {
+                eprintln!("unrecognized argument: {arg}");
+                std::process::exit(1);
+            }
+        }
+    }
 
     let mut code = Vec::new();
+    File::open(path)?.read_to_end(&mut code)?;
+    assert!(code.len() < MAP_SIZE);
 
-    // Read the passed file into the `code` buffer
-    File::open(env::args().nth(1).expect("no argument passed"))?.read_to_end(&mut code)?;
-
-    let kvm = Kvm::new()?;
-
-    // Mapping to store the code
-    // MAP_ANONYMOUS is used as we're not backing this mapping by any fd
-    let mapping = WrappedAutoFree::new(
-        unsafe {
-            mman::mmap(
-                None,
-                NonZeroUsize::new(MAP_SIZE).expect("mapping size is zero"),
-                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
-                MapFlags::MAP_ANONYMOUS | MapFlags::MAP_SHARED,
-                None::<BorrowedFd>,
-                0,
-            )?
-        },
-        |map| unsafe {
-            mman::munmap(map, MAP_SIZE).expect("failed to unmap user memory region");
-        },
-    );
+    let (mut vm, vcpus) = VmBuilder::new().vcpu_count(vcpu_count).build()?;
 
-    assert!(code.len() < MAP_SIZE);
+    // Code is shared - every vCPU starts executing it at guest-physical address 0
+    let region = vm.add_region(0, MAP_SIZE, false, false)?;
+    region.as_mut_slice()[..code.len()].copy_from_slice(&code);
 
-    // The idiomatic way is to write a wrapper struct for `mmap`-ing regions
-    // and exposing it as a slice (std::slice::from_raw_parts)
-    // But we just copy the code directly here
-    unsafe {
-        std::ptr::copy_nonoverlapping(code.as_ptr(), *mapping as *mut _, code.len());
-    };
-
-    let mut sregs = kvm.get_vcpu_sregs()?;
-
-    // CS points to the reset vector by default
-    sregs.cs.base = 0;
-    sregs.cs.selector = 0;
-
-    kvm.set_vcpu_sregs(&sregs)?;
-    kvm.set_user_memory_region(0, MAP_SIZE, *mapping as u64)?;
-    kvm.set_vcpu_regs(&kvm_regs {
-        // The first bit must be set on x86
-        rflags: 1 << 1,
-        // The instruction pointer is set to 0 as our code is loaded with 0
-        // as the base address
-        rip: 0,
-        ..Default::default()
-    })?;
-
-    loop {
-        let kvm_run = kvm.run()?;
-
-        unsafe {
-            match (*kvm_run).exit_reason {
-                KVM_EXIT_HLT => break,
-                KVM_EXIT_IO => {
-                    let port = (*kvm_run).__bindgen_anon_1.io.port;
-                    let offset = (*kvm_run).__bindgen_anon_1.io.data_offset as usize;
-                    let character = *((kvm_run as *const u8).add(offset)) as char;
-
-                    println!("Port: {port:#x}, Char: {character}");
-                }
-                reason => panic!("Unhandled exit reason: {reason}"),
+    // Every vCPU gets its own stack, so they don't stomp on each other's
+    for id in 0..vcpu_count {
+        vm.add_region(
+            STACKS_BASE + id * STACK_SIZE as u64,
+            STACK_SIZE,
+            false,
+            false,
+        )?;
+    }
+
+    // 16550 UART at COM1, spanning its 8 registers, shared by every vCPU
+    const SERIAL_BASE: u64 = 0x3F8;
+    let serial = Arc::new(Mutex::new(Serial::new()));
+
+    let mut io_bus = Bus::new();
+    io_bus.register(SERIAL_BASE, 8, Box::new(serial.clone()));
+    let io_bus = Arc::new(Mutex::new(io_bus));
+    let mmio_bus = Arc::new(Mutex::new(Bus::new()));
+
+    // Set by whichever vCPU halts first, to wind the rest down
+    let halted = Arc::new(AtomicBool::new(false));
+
+    let handles = vcpus
+        .into_iter()
+        .enumerate()
+        .map(|(id, vcpu)| {
+            // vCPU 0 resumes from a snapshot if one was given and already
+            // exists on disk; every other vCPU (and vCPU 0 on a fresh run)
+            // gets the usual reset state
+            let resumed = id == 0
+                && snapshot_path
+                    .as_deref()
+                    .is_some_and(|path| Path::new(path).exists());
+
+            if resumed {
+                vcpu.restore(&Snapshot::load(snapshot_path.as_deref().unwrap())?)?;
+            } else {
+                // CS points to the reset vector by default
+                let mut sregs = vcpu.get_sregs()?;
+                sregs.cs.base = 0;
+                sregs.cs.selector = 0;
+                vcpu.set_sregs(&sregs)?;
+                vcpu.set_regs(&kvm_regs {
+                    // The first bit must be set on x86
+                    rflags: 1 << 1,
+                    // Every vCPU starts at the same entry point...
+                    rip: 0,
+                    // ...but gets its own stack
+                    rsp: STACKS_BASE + (id as u64 + 1) * STACK_SIZE as u64,
+                    ..Default::default()
+                })?;
             }
+
+            let serial = serial.clone();
+            let io_bus = io_bus.clone();
+            let mmio_bus = mmio_bus.clone();
+            let halted = halted.clone();
+            let snapshot_path = snapshot_path.clone();
+
+            Ok(thread::spawn(move || -> Result<(), std::io::Error> {
+                // Set on every exit path, including an unwinding panic, so a
+                // dying thread still signals the rest to wind down
+                let _halt_on_drop = HaltOnDrop(halted.clone());
+
+                while !halted.load(Ordering::Acquire) {
+                    // Only vCPU 0 drains stdin, so we don't race several
+                    // threads over the same fd
+                    if id == 0 {
+                        poll_stdin(&serial)?;
+                    }
+
+                    let kvm_run = vcpu.run()?;
+
+                    unsafe {
+                        match (*kvm_run).exit_reason {
+                            KVM_EXIT_HLT => {
+                                if id == 0 {
+                                    if let Some(path) = &snapshot_path {
+                                        vcpu.snapshot()?.dump(path)?;
+                                    }
+                                }
+                                break;
+                            }
+                            KVM_EXIT_IO => {
+                                let io = (*kvm_run).__bindgen_anon_1.io;
+                                let offset = io.data_offset as usize;
+                                let len = io.size as usize * io.count as usize;
+
+                                match u32::from(io.direction) {
+                                    KVM_EXIT_IO_OUT => {
+                                        let data = std::slice::from_raw_parts(
+                                            (kvm_run as *const u8).add(offset),
+                                            len,
+                                        );
+                                        io_bus.lock().unwrap().write(io.port.into(), data);
+                                    }
+                                    KVM_EXIT_IO_IN => {
+                                        let data = std::slice::from_raw_parts_mut(
+                                            (kvm_run as *mut u8).add(offset),
+                                            len,
+                                        );
+                                        io_bus.lock().unwrap().read(io.port.into(), data);
+                                    }
+                                    direction => {
+                                        panic!("unknown KVM_EXIT_IO direction: {direction}")
+                                    }
+                                }
+                            }
+                            KVM_EXIT_MMIO => {
+                                let mmio = &mut (*kvm_run).__bindgen_anon_1.mmio;
+                                let len = mmio.len as usize;
+                                let mut mmio_bus = mmio_bus.lock().unwrap();
+
+                                if mmio.is_write != 0 {
+                                    if !mmio_bus.write(mmio.phys_addr, &mmio.data[..len]) {
+                                        panic!(
+                                            "vcpu {id}: guest attempted to write {len} byte(s) \
+                                             to unmapped/read-only address {:#x}",
+                                            mmio.phys_addr
+                                        );
+                                    }
+                                } else if !mmio_bus.read(mmio.phys_addr, &mut mmio.data[..len]) {
+                                    panic!("vcpu {id}: unhandled MMIO read at {:#x}", mmio.phys_addr);
+                                }
+                            }
+                            reason => panic!("vcpu {id}: unhandled exit reason: {reason}"),
+                        }
+                    }
+                }
+
+                Ok(())
+            }))
+        })
+        .collect::<Result<Vec<_>, std::io::Error>>()?;
+
+    // Join whichever thread finishes first, not the one spawned first - a
+    // panicking thread still flips `halted` via `HaltOnDrop`, but the others
+    // only notice it between `vcpu.run()` calls, so they can finish in any
+    // order. Joining strictly by spawn order would block on a thread that's
+    // still happily running while a later one already panicked
+    let mut handles = handles;
+    while !handles.is_empty() {
+        match handles.iter().position(thread::JoinHandle::is_finished) {
+            Some(i) => handles.remove(i).join().expect("vcpu thread panicked")?,
+            None => thread::sleep(Duration::from_millis(10)),
         }
     }
 
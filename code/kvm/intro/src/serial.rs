@@ -0,0 +1,113 @@
+use crate::bus::Device;
+use std::{
+    collections::VecDeque,
+    io::{self, Write},
+};
+
+const THR_RBR: u64 = 0;
+const IER: u64 = 1;
+const FCR: u64 = 2;
+const LCR: u64 = 3;
+const MCR: u64 = 4;
+const LSR: u64 = 5;
+const MSR: u64 = 6;
+const SCRATCH: u64 = 7;
+
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_THR_EMPTY: u8 = 1 << 5;
+
+/// Divisor Latch Access Bit of LCR. While set, offsets 0 and 1 address the
+/// baud rate divisor latch (DLL/DLM) instead of THR/RBR and IER
+const LCR_DLAB: u8 = 1 << 7;
+
+/// A minimal 16550-compatible UART, bound to port base `0x3F8` (COM1). Only
+/// enough of the register set is modeled for a guest's serial driver probe to
+/// succeed and for THR/RBR to carry a byte stream - there's no FIFO, no
+/// interrupts, MCR/IER are just latched, not acted upon, and FCR writes are ignored
+pub struct Serial {
+    input: VecDeque<u8>,
+    ier: u8,
+    lcr: u8,
+    mcr: u8,
+    scratch: u8,
+    // Latched but otherwise unused - we don't model a baud rate
+    dll: u8,
+    dlm: u8,
+}
+
+impl Serial {
+    pub fn new() -> Self {
+        Self {
+            input: VecDeque::new(),
+            ier: 0,
+            lcr: 0,
+            mcr: 0,
+            scratch: 0,
+            dll: 0,
+            dlm: 0,
+        }
+    }
+
+    /// Feeds host-provided bytes into the receive buffer; a subsequent guest
+    /// read of RBR drains them in order
+    pub fn enqueue_input(&mut self, bytes: &[u8]) {
+        self.input.extend(bytes);
+    }
+
+    fn dlab(&self) -> bool {
+        self.lcr & LCR_DLAB != 0
+    }
+
+    fn lsr(&self) -> u8 {
+        // The transmitter is a direct `print!`, so it's always empty/ready
+        LSR_THR_EMPTY | if self.input.is_empty() { 0 } else { LSR_DATA_READY }
+    }
+}
+
+impl Default for Serial {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Device for Serial {
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        let Some(byte) = data.first_mut() else {
+            return;
+        };
+
+        *byte = match offset {
+            THR_RBR if self.dlab() => self.dll,
+            THR_RBR => self.input.pop_front().unwrap_or(0),
+            IER if self.dlab() => self.dlm,
+            IER => self.ier,
+            LCR => self.lcr,
+            MCR => self.mcr,
+            LSR => self.lsr(),
+            MSR => 0,
+            SCRATCH => self.scratch,
+            _ => 0,
+        };
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        let Some(&byte) = data.first() else {
+            return;
+        };
+
+        match offset {
+            THR_RBR if self.dlab() => self.dll = byte,
+            THR_RBR => {
+                print!("{}", byte as char);
+                let _ = io::stdout().flush();
+            }
+            IER if self.dlab() => self.dlm = byte,
+            IER => self.ier = byte,
+            FCR => {} // FIFO control, write-only, no FIFOs modeled
+            LCR => self.lcr = byte,
+            MCR => self.mcr = byte,
+            SCRATCH => self.scratch = byte,
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,38 @@
+use kvm_bindings::{kvm_fpu, kvm_regs, kvm_sregs};
+use std::{
+    fs::File,
+    io::{Read, Write},
+    mem, slice,
+};
+
+/// A full, restorable vCPU snapshot: general-purpose registers, segment/control
+/// registers, and the FPU/SSE state. Without the FPU half, restoring a guest that
+/// was mid floating-point computation would corrupt its x87/XMM registers
+#[repr(C)]
+pub struct Snapshot {
+    pub regs: kvm_regs,
+    pub sregs: kvm_sregs,
+    pub fpu: kvm_fpu,
+}
+
+impl Snapshot {
+    /// Writes the snapshot to `path` as a raw byte dump of this struct
+    pub fn dump(&self, path: &str) -> Result<(), std::io::Error> {
+        let bytes =
+            unsafe { slice::from_raw_parts(self as *const Self as *const u8, mem::size_of::<Self>()) };
+
+        File::create(path)?.write_all(bytes)
+    }
+
+    /// Reads back a snapshot previously written by `dump`
+    pub fn load(path: &str) -> Result<Self, std::io::Error> {
+        let mut snapshot = mem::MaybeUninit::<Self>::uninit();
+
+        let bytes = unsafe {
+            slice::from_raw_parts_mut(snapshot.as_mut_ptr() as *mut u8, mem::size_of::<Self>())
+        };
+        File::open(path)?.read_exact(bytes)?;
+
+        Ok(unsafe { snapshot.assume_init() })
+    }
+}
@@ -0,0 +1,132 @@
+use crate::snapshot::Snapshot;
+use intro::WrappedAutoFree;
+use kvm_bindings::{kvm_fpu, kvm_regs, kvm_run, kvm_sregs, KVMIO};
+use nix::{
+    ioctl_read, ioctl_write_int_bad, ioctl_write_ptr, request_code_none,
+    sys::{mman, mman::MapFlags, mman::ProtFlags},
+};
+use std::{
+    num::NonZeroUsize,
+    os::fd::{AsRawFd, OwnedFd, RawFd},
+};
+
+ioctl_write_int_bad!(kvm_get_vcpu_mmap_size, request_code_none!(KVMIO, 0x04));
+ioctl_write_int_bad!(kvm_run_ioctl, request_code_none!(KVMIO, 0x80));
+ioctl_read!(kvm_get_regs, KVMIO, 0x81, kvm_regs);
+ioctl_write_ptr!(kvm_set_regs, KVMIO, 0x82, kvm_regs);
+ioctl_read!(kvm_get_sregs, KVMIO, 0x83, kvm_sregs);
+ioctl_write_ptr!(kvm_set_sregs, KVMIO, 0x84, kvm_sregs);
+ioctl_read!(kvm_get_fpu, KVMIO, 0x8c, kvm_fpu);
+ioctl_write_ptr!(kvm_set_fpu, KVMIO, 0x8d, kvm_fpu);
+
+/// One vCPU of a `Vm`: its fd, its own `mmap`'d `kvm_run` page, and the usual
+/// register get/set calls. Every field here is private to the vCPU that owns
+/// it, so a `Vcpu` can be handed off to the thread that's going to drive it
+pub struct Vcpu {
+    vcpu: OwnedFd,
+    kvm_run: WrappedAutoFree<*mut kvm_run, Box<dyn FnOnce(*mut kvm_run)>>,
+}
+
+impl Vcpu {
+    /// Maps the `kvm_run` page for an already-created `vcpu` fd. `kvm` is the
+    /// `/dev/kvm` fd, needed only to size the mapping
+    pub(crate) fn new(kvm: RawFd, vcpu: OwnedFd) -> Result<Self, std::io::Error> {
+        let mmap_size = NonZeroUsize::new(unsafe {
+            kvm_get_vcpu_mmap_size(kvm, 0)?
+                .try_into()
+                .expect("mmap_size too big for usize!")
+        })
+        .expect("mmap_size is zero");
+
+        let kvm_run = WrappedAutoFree::new(
+            unsafe {
+                mman::mmap(
+                    None,
+                    mmap_size,
+                    ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+                    MapFlags::MAP_SHARED,
+                    Some(&vcpu),
+                    0,
+                )? as *mut kvm_run
+            },
+            Box::new(move |map: *mut kvm_run| unsafe {
+                mman::munmap(map as _, mmap_size.get()).expect("failed to unmap kvm_run!");
+            }) as _,
+        );
+
+        Ok(Self { vcpu, kvm_run })
+    }
+
+    pub fn get_sregs(&self) -> Result<kvm_sregs, std::io::Error> {
+        let mut sregs = kvm_sregs::default();
+        unsafe { kvm_get_sregs(self.vcpu.as_raw_fd(), &mut sregs)? };
+
+        Ok(sregs)
+    }
+
+    pub fn set_sregs(&self, regs: *const kvm_sregs) -> Result<(), std::io::Error> {
+        unsafe { kvm_set_sregs(self.vcpu.as_raw_fd(), regs)? };
+
+        Ok(())
+    }
+
+    pub fn get_regs(&self) -> Result<kvm_regs, std::io::Error> {
+        let mut regs = kvm_regs::default();
+        unsafe { kvm_get_regs(self.vcpu.as_raw_fd(), &mut regs)? };
+
+        Ok(regs)
+    }
+
+    pub fn set_regs(&self, regs: *const kvm_regs) -> Result<(), std::io::Error> {
+        unsafe { kvm_set_regs(self.vcpu.as_raw_fd(), regs)? };
+
+        Ok(())
+    }
+
+    pub fn get_fpu(&self) -> Result<kvm_fpu, std::io::Error> {
+        let mut fpu = kvm_fpu::default();
+        unsafe { kvm_get_fpu(self.vcpu.as_raw_fd(), &mut fpu)? };
+
+        Ok(fpu)
+    }
+
+    pub fn set_fpu(&self, fpu: *const kvm_fpu) -> Result<(), std::io::Error> {
+        unsafe { kvm_set_fpu(self.vcpu.as_raw_fd(), fpu)? };
+
+        Ok(())
+    }
+
+    /// Bundles the full restorable vCPU state (regs, sregs, fpu) into one snapshot
+    pub fn snapshot(&self) -> Result<Snapshot, std::io::Error> {
+        Ok(Snapshot {
+            regs: self.get_regs()?,
+            sregs: self.get_sregs()?,
+            fpu: self.get_fpu()?,
+        })
+    }
+
+    /// Restores the vCPU to a previously captured snapshot
+    pub fn restore(&self, snapshot: &Snapshot) -> Result<(), std::io::Error> {
+        self.set_regs(&snapshot.regs)?;
+        self.set_sregs(&snapshot.sregs)?;
+        self.set_fpu(&snapshot.fpu)?;
+
+        Ok(())
+    }
+
+    pub fn run(&self) -> Result<*mut kvm_run, std::io::Error> {
+        unsafe {
+            kvm_run_ioctl(self.vcpu.as_raw_fd(), 0)?;
+        }
+
+        // The `kvm_run` struct is filled with new data as it was associated
+        // with the `vcpu` FD in the mmap() call. The mapping is genuinely
+        // writable - the run loop writes MMIO/IO results back into it before
+        // re-entering KVM_RUN
+        Ok(*self.kvm_run)
+    }
+}
+
+// `kvm_run` is `*mut` and thus `!Send` by default, but nothing else ever holds
+// a reference to a vCPU's mapping once it's handed to its owning thread
+unsafe impl Send for Vcpu {}
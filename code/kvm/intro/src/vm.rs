@@ -0,0 +1,87 @@
+use crate::memory::{GuestMemory, MemoryRegion};
+use crate::vcpu::Vcpu;
+use kvm_bindings::KVMIO;
+use nix::{fcntl, fcntl::OFlag, ioctl_write_int_bad, request_code_none, sys::stat::Mode};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+ioctl_write_int_bad!(kvm_create_vm, request_code_none!(KVMIO, 0x01));
+ioctl_write_int_bad!(kvm_create_vcpu, request_code_none!(KVMIO, 0x41));
+
+/// A VM: the `/dev/kvm` and VM fds, plus the guest-physical memory shared by
+/// every vCPU created from it. `memory` is declared before `vm` so its regions
+/// are unmapped from the VM before the VM fd is closed
+pub struct Vm {
+    kvm: OwnedFd,
+    memory: GuestMemory,
+    vm: OwnedFd,
+}
+
+impl Vm {
+    fn new() -> Result<Self, std::io::Error> {
+        let kvm =
+            unsafe { OwnedFd::from_raw_fd(fcntl::open("/dev/kvm", OFlag::O_RDWR, Mode::empty())?) };
+        let vm = unsafe { OwnedFd::from_raw_fd(kvm_create_vm(kvm.as_raw_fd(), 0)?) };
+        let memory = GuestMemory::new(vm.as_raw_fd());
+
+        Ok(Self { kvm, memory, vm })
+    }
+
+    /// Maps `size` bytes of guest-physical memory at `guest_phys_addr`, shared by
+    /// every vCPU this `Vm` creates. See `GuestMemory::add_region` for overlap and
+    /// read-only handling
+    pub fn add_region(
+        &mut self,
+        guest_phys_addr: u64,
+        size: usize,
+        read_only: bool,
+        log_dirty: bool,
+    ) -> Result<&mut MemoryRegion, std::io::Error> {
+        self.memory
+            .add_region(guest_phys_addr, size, read_only, log_dirty)
+    }
+
+    fn create_vcpu(&self, id: u64) -> Result<Vcpu, std::io::Error> {
+        // KVM_CREATE_VCPU takes its index as an int, not a u64
+        let vcpu = unsafe {
+            OwnedFd::from_raw_fd(kvm_create_vcpu(
+                self.vm.as_raw_fd(),
+                id.try_into().expect("vcpu id doesn't fit in an i32"),
+            )?)
+        };
+
+        Vcpu::new(self.kvm.as_raw_fd(), vcpu)
+    }
+}
+
+/// Builds a `Vm` together with the vCPUs it will be run with
+pub struct VmBuilder {
+    vcpu_count: u64,
+}
+
+impl VmBuilder {
+    pub fn new() -> Self {
+        Self { vcpu_count: 1 }
+    }
+
+    /// Sets the number of vCPUs to create, each with its own `kvm_run` mapping
+    pub fn vcpu_count(mut self, vcpu_count: u64) -> Self {
+        self.vcpu_count = vcpu_count;
+        self
+    }
+
+    /// Creates the VM and every requested vCPU, indexed `0..vcpu_count`
+    pub fn build(self) -> Result<(Vm, Vec<Vcpu>), std::io::Error> {
+        let vm = Vm::new()?;
+        let vcpus = (0..self.vcpu_count)
+            .map(|id| vm.create_vcpu(id))
+            .collect::<Result<_, _>>()?;
+
+        Ok((vm, vcpus))
+    }
+}
+
+impl Default for VmBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}